@@ -10,14 +10,42 @@ use crate::state::ThreadSafeState;
 use deno_core::*;
 use futures::future::FutureExt;
 use std;
+use std::collections::HashMap;
 use std::convert::From;
 use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::Mutex;
 use tokio;
 
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(windows)]
+use std::os::windows::io::AsRawHandle;
+
+// StreamResource::FsFile doesn't carry the path it was opened from, so ops
+// that act on an already-open rid (ftruncate, read_at, write_at) keep their
+// own rid -> resolved path map here, filled in by op_open and drained by
+// op_close, to re-check permissions against the real path the same way
+// op_open does instead of a path-less global check.
+static OPEN_FILE_PATHS: Mutex<Option<HashMap<u32, PathBuf>>> = Mutex::new(None);
+
+fn with_open_file_paths<T>(f: impl FnOnce(&mut HashMap<u32, PathBuf>) -> T) -> T {
+  let mut guard = OPEN_FILE_PATHS.lock().unwrap();
+  f(guard.get_or_insert_with(HashMap::new))
+}
+
 pub fn init(i: &mut Isolate, s: &ThreadSafeState) {
   i.register_op("open", s.core_op(json_op(s.stateful_op(op_open))));
   i.register_op("close", s.core_op(json_op(s.stateful_op(op_close))));
   i.register_op("seek", s.core_op(json_op(s.stateful_op(op_seek))));
+  i.register_op(
+    "ftruncate",
+    s.core_op(json_op(s.stateful_op(op_ftruncate))),
+  );
+  i.register_op("readAt", s.core_op(json_op(s.stateful_op(op_read_at))));
+  i.register_op("writeAt", s.core_op(json_op(s.stateful_op(op_write_at))));
+  i.register_op("flock", s.core_op(json_op(s.stateful_op(op_flock))));
+  i.register_op("funlock", s.core_op(json_op(s.stateful_op(op_funlock))));
 }
 
 #[derive(Deserialize)]
@@ -37,6 +65,7 @@ struct OpenOptions {
   truncate: bool,
   append: bool,
   create_new: bool,
+  mode: Option<u32>,
 }
 
 fn op_open(
@@ -58,6 +87,14 @@ fn op_open(
     .append(capability.append)
     .create_new(capability.create_new);
 
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::OpenOptionsExt;
+    if let Some(mode) = capability.mode {
+      open_options.mode(mode);
+    }
+  }
+
   if capability.read {
     state.check_read(&filename_)?;
   }
@@ -67,11 +104,13 @@ fn op_open(
   }
 
   let is_sync = args.promise_id.is_none();
+  let opened_path = filename_.clone();
 
   let fut = async move {
     let fs_file = open_options.open(filename).await?;
     let mut table = state_.lock_resource_table();
     let rid = table.add("fsFile", Box::new(StreamResource::FsFile(fs_file)));
+    with_open_file_paths(|paths| paths.insert(rid, opened_path));
     Ok(json!(rid))
   };
 
@@ -94,9 +133,11 @@ fn op_close(
   _zero_copy: Option<PinnedBuf>,
 ) -> Result<JsonOp, ErrBox> {
   let args: CloseArgs = serde_json::from_value(args)?;
+  let rid = args.rid as u32;
 
   let mut table = state.lock_resource_table();
-  table.close(args.rid as u32).ok_or_else(bad_resource)?;
+  table.close(rid).ok_or_else(bad_resource)?;
+  with_open_file_paths(|paths| paths.remove(&rid));
   Ok(JsonOp::Sync(json!({})))
 }
 
@@ -143,7 +184,398 @@ fn op_seek(
   let mut file = futures::executor::block_on(tokio_file.try_clone())?;
 
   let fut = async move {
-    file.seek(seek_from).await?;
+    let pos = file.seek(seek_from).await?;
+    Ok(json!(pos))
+  };
+
+  if args.promise_id.is_none() {
+    let buf = futures::executor::block_on(fut)?;
+    Ok(JsonOp::Sync(buf))
+  } else {
+    Ok(JsonOp::Async(fut.boxed()))
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FtruncateArgs {
+  promise_id: Option<u64>,
+  rid: i32,
+  len: u64,
+}
+
+fn op_ftruncate(
+  state: &ThreadSafeState,
+  args: Value,
+  _zero_copy: Option<PinnedBuf>,
+) -> Result<JsonOp, ErrBox> {
+  let args: FtruncateArgs = serde_json::from_value(args)?;
+  let rid = args.rid as u32;
+  let len = args.len;
+
+  let path = with_open_file_paths(|paths| paths.get(&rid).cloned())
+    .ok_or_else(bad_resource)?;
+  state.check_write(&path)?;
+
+  let mut table = state.lock_resource_table();
+  let resource = table
+    .get_mut::<StreamResource>(rid)
+    .ok_or_else(bad_resource)?;
+
+  let tokio_file = match resource {
+    StreamResource::FsFile(ref mut file) => file,
+    _ => return Err(bad_resource()),
+  };
+  let file = futures::executor::block_on(tokio_file.try_clone())?;
+
+  let fut = async move {
+    file.set_len(len).await?;
+    Ok(json!({}))
+  };
+
+  if args.promise_id.is_none() {
+    let buf = futures::executor::block_on(fut)?;
+    Ok(JsonOp::Sync(buf))
+  } else {
+    Ok(JsonOp::Async(fut.boxed()))
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReadWriteAtArgs {
+  promise_id: Option<u64>,
+  rid: i32,
+  offset: u64,
+}
+
+// Positional IO must not go through seek(): a tokio::fs::File clone shares
+// the same underlying open-file-description (dup(2) on Unix, a duplicated
+// handle on Windows) as the original, so seeking the clone moves the real
+// file's cursor too and races with any concurrent read/write on the same
+// rid. pread(2)/pwrite(2) and Windows' offset-qualified ReadFile/WriteFile
+// take the offset as an argument instead of relying on the shared cursor.
+#[cfg(unix)]
+fn sys_pread(file: &tokio::fs::File, buf: &mut [u8], offset: u64) -> Result<usize, ErrBox> {
+  let fd = file.as_raw_fd();
+  let nread = unsafe {
+    libc::pread(
+      fd,
+      buf.as_mut_ptr() as *mut libc::c_void,
+      buf.len(),
+      offset as libc::off_t,
+    )
+  };
+  if nread < 0 {
+    return Err(ErrBox::from(std::io::Error::last_os_error()));
+  }
+  Ok(nread as usize)
+}
+
+#[cfg(unix)]
+fn sys_pwrite(file: &tokio::fs::File, buf: &[u8], offset: u64) -> Result<usize, ErrBox> {
+  let fd = file.as_raw_fd();
+  let nwritten = unsafe {
+    libc::pwrite(
+      fd,
+      buf.as_ptr() as *const libc::c_void,
+      buf.len(),
+      offset as libc::off_t,
+    )
+  };
+  if nwritten < 0 {
+    return Err(ErrBox::from(std::io::Error::last_os_error()));
+  }
+  Ok(nwritten as usize)
+}
+
+#[cfg(windows)]
+fn sys_pread(file: &tokio::fs::File, buf: &mut [u8], offset: u64) -> Result<usize, ErrBox> {
+  use std::mem;
+  use winapi::um::fileapi::ReadFile;
+  use winapi::um::minwinbase::OVERLAPPED;
+
+  let handle = file.as_raw_handle();
+  let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+  overlapped.Offset = (offset & 0xFFFF_FFFF) as u32;
+  overlapped.OffsetHigh = (offset >> 32) as u32;
+  let mut nread: u32 = 0;
+  let res = unsafe {
+    ReadFile(
+      handle as *mut _,
+      buf.as_mut_ptr() as *mut _,
+      buf.len() as u32,
+      &mut nread,
+      &mut overlapped,
+    )
+  };
+  if res == 0 {
+    return Err(ErrBox::from(std::io::Error::last_os_error()));
+  }
+  Ok(nread as usize)
+}
+
+#[cfg(windows)]
+fn sys_pwrite(file: &tokio::fs::File, buf: &[u8], offset: u64) -> Result<usize, ErrBox> {
+  use std::mem;
+  use winapi::um::fileapi::WriteFile;
+  use winapi::um::minwinbase::OVERLAPPED;
+
+  let handle = file.as_raw_handle();
+  let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+  overlapped.Offset = (offset & 0xFFFF_FFFF) as u32;
+  overlapped.OffsetHigh = (offset >> 32) as u32;
+  let mut nwritten: u32 = 0;
+  let res = unsafe {
+    WriteFile(
+      handle as *mut _,
+      buf.as_ptr() as *const _,
+      buf.len() as u32,
+      &mut nwritten,
+      &mut overlapped,
+    )
+  };
+  if res == 0 {
+    return Err(ErrBox::from(std::io::Error::last_os_error()));
+  }
+  Ok(nwritten as usize)
+}
+
+fn op_read_at(
+  state: &ThreadSafeState,
+  args: Value,
+  zero_copy: Option<PinnedBuf>,
+) -> Result<JsonOp, ErrBox> {
+  let args: ReadWriteAtArgs = serde_json::from_value(args)?;
+  let rid = args.rid as u32;
+  let offset = args.offset;
+  let mut zero_copy = zero_copy.ok_or_else(|| {
+    ErrBox::from(DenoError::new(
+      ErrorKind::InvalidInput,
+      "read_at requires a buffer".to_string(),
+    ))
+  })?;
+
+  // Defense in depth: a read grant can be revoked after the file was
+  // opened, so re-check against the resolved path, same as op_open.
+  let path = with_open_file_paths(|paths| paths.get(&rid).cloned())
+    .ok_or_else(bad_resource)?;
+  state.check_read(&path)?;
+
+  let mut table = state.lock_resource_table();
+  let resource = table
+    .get_mut::<StreamResource>(rid)
+    .ok_or_else(bad_resource)?;
+
+  let tokio_file = match resource {
+    StreamResource::FsFile(ref mut file) => file,
+    _ => return Err(bad_resource()),
+  };
+  let file = futures::executor::block_on(tokio_file.try_clone())?;
+
+  let fut = async move {
+    let nread = sys_pread(&file, &mut zero_copy, offset)?;
+    Ok(json!(nread))
+  };
+
+  if args.promise_id.is_none() {
+    let buf = futures::executor::block_on(fut)?;
+    Ok(JsonOp::Sync(buf))
+  } else {
+    Ok(JsonOp::Async(fut.boxed()))
+  }
+}
+
+fn op_write_at(
+  state: &ThreadSafeState,
+  args: Value,
+  zero_copy: Option<PinnedBuf>,
+) -> Result<JsonOp, ErrBox> {
+  let args: ReadWriteAtArgs = serde_json::from_value(args)?;
+  let rid = args.rid as u32;
+  let offset = args.offset;
+  let zero_copy = zero_copy.ok_or_else(|| {
+    ErrBox::from(DenoError::new(
+      ErrorKind::InvalidInput,
+      "write_at requires a buffer".to_string(),
+    ))
+  })?;
+
+  let path = with_open_file_paths(|paths| paths.get(&rid).cloned())
+    .ok_or_else(bad_resource)?;
+  state.check_write(&path)?;
+
+  let mut table = state.lock_resource_table();
+  let resource = table
+    .get_mut::<StreamResource>(rid)
+    .ok_or_else(bad_resource)?;
+
+  let tokio_file = match resource {
+    StreamResource::FsFile(ref mut file) => file,
+    _ => return Err(bad_resource()),
+  };
+  let file = futures::executor::block_on(tokio_file.try_clone())?;
+
+  let fut = async move {
+    let nwritten = sys_pwrite(&file, &zero_copy, offset)?;
+    Ok(json!(nwritten))
+  };
+
+  if args.promise_id.is_none() {
+    let buf = futures::executor::block_on(fut)?;
+    Ok(JsonOp::Sync(buf))
+  } else {
+    Ok(JsonOp::Async(fut.boxed()))
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FlockArgs {
+  promise_id: Option<u64>,
+  rid: i32,
+  exclusive: bool,
+}
+
+// Always takes the real blocking lock: a caller that awaits Deno.flock()
+// wants to wait until the lock is free, not fail fast on first contention.
+#[cfg(unix)]
+fn sys_flock(file: &tokio::fs::File, exclusive: bool) -> Result<(), ErrBox> {
+  let fd = file.as_raw_fd();
+  let flags = if exclusive {
+    libc::LOCK_EX
+  } else {
+    libc::LOCK_SH
+  };
+  let res = unsafe { libc::flock(fd, flags) };
+  if res != 0 {
+    return Err(ErrBox::from(std::io::Error::last_os_error()));
+  }
+  Ok(())
+}
+
+#[cfg(unix)]
+fn sys_funlock(file: &tokio::fs::File) -> Result<(), ErrBox> {
+  let fd = file.as_raw_fd();
+  let res = unsafe { libc::flock(fd, libc::LOCK_UN) };
+  if res != 0 {
+    return Err(ErrBox::from(std::io::Error::last_os_error()));
+  }
+  Ok(())
+}
+
+#[cfg(windows)]
+fn sys_flock(file: &tokio::fs::File, exclusive: bool) -> Result<(), ErrBox> {
+  use std::mem;
+  use winapi::shared::minwindef::DWORD;
+  use winapi::um::fileapi::LockFileEx;
+  use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, OVERLAPPED};
+
+  let handle = file.as_raw_handle();
+  let mut flags: DWORD = 0;
+  if exclusive {
+    flags |= LOCKFILE_EXCLUSIVE_LOCK;
+  }
+  let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+  let res = unsafe {
+    LockFileEx(
+      handle as *mut _,
+      flags,
+      0,
+      !0,
+      !0,
+      &mut overlapped,
+    )
+  };
+  if res == 0 {
+    return Err(ErrBox::from(std::io::Error::last_os_error()));
+  }
+  Ok(())
+}
+
+#[cfg(windows)]
+fn sys_funlock(file: &tokio::fs::File) -> Result<(), ErrBox> {
+  use std::mem;
+  use winapi::um::fileapi::UnlockFileEx;
+  use winapi::um::minwinbase::OVERLAPPED;
+
+  let handle = file.as_raw_handle();
+  let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+  let res = unsafe { UnlockFileEx(handle as *mut _, 0, !0, !0, &mut overlapped) };
+  if res == 0 {
+    return Err(ErrBox::from(std::io::Error::last_os_error()));
+  }
+  Ok(())
+}
+
+fn op_flock(
+  state: &ThreadSafeState,
+  args: Value,
+  _zero_copy: Option<PinnedBuf>,
+) -> Result<JsonOp, ErrBox> {
+  let args: FlockArgs = serde_json::from_value(args)?;
+  let rid = args.rid as u32;
+  let exclusive = args.exclusive;
+  let is_sync = args.promise_id.is_none();
+
+  let mut table = state.lock_resource_table();
+  let resource = table
+    .get_mut::<StreamResource>(rid)
+    .ok_or_else(bad_resource)?;
+
+  let tokio_file = match resource {
+    StreamResource::FsFile(ref mut file) => file,
+    _ => return Err(bad_resource()),
+  };
+  let file = futures::executor::block_on(tokio_file.try_clone())?;
+
+  if is_sync {
+    // The sync call already runs on the calling thread with the
+    // expectation that it blocks until done, same as every other sync op.
+    sys_flock(&file, exclusive)?;
+    Ok(JsonOp::Sync(json!({})))
+  } else {
+    // Run the real blocking lock on the blocking thread pool so the
+    // promise only resolves once the lock is actually acquired, without
+    // stalling the isolate while it waits out contention.
+    let fut = async move {
+      tokio::task::spawn_blocking(move || sys_flock(&file, exclusive))
+        .await
+        .map_err(ErrBox::from)??;
+      Ok(json!({}))
+    };
+    Ok(JsonOp::Async(fut.boxed()))
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FunlockArgs {
+  promise_id: Option<u64>,
+  rid: i32,
+}
+
+fn op_funlock(
+  state: &ThreadSafeState,
+  args: Value,
+  _zero_copy: Option<PinnedBuf>,
+) -> Result<JsonOp, ErrBox> {
+  let args: FunlockArgs = serde_json::from_value(args)?;
+  let rid = args.rid as u32;
+
+  let mut table = state.lock_resource_table();
+  let resource = table
+    .get_mut::<StreamResource>(rid)
+    .ok_or_else(bad_resource)?;
+
+  let tokio_file = match resource {
+    StreamResource::FsFile(ref mut file) => file,
+    _ => return Err(bad_resource()),
+  };
+  let file = futures::executor::block_on(tokio_file.try_clone())?;
+
+  let fut = async move {
+    sys_funlock(&file)?;
     Ok(json!({}))
   };
 